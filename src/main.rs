@@ -3,28 +3,149 @@ use matrix_bot_api::{
 	handlers::{HandleResult, stateless_handler::StatelessHandler}
 };
 use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
-use std::sync::Mutex;
-use std::thread::{spawn, sleep};
-use std::time::Duration;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+// A crate stops being reported as erroring as soon as it succeeds again;
+// this just keeps a single transient crates.io hiccup from paging a room.
+const FAILURE_REPORT_THRESHOLD: u32 = 3;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CrateState {
+	version: String,
+	#[serde(default)]
+	consecutive_failures: u32
+}
+
+// Everything that gets written to `--data-file`: the watch lists plus the
+// live-adjustable knobs that should survive a restart.
+#[derive(Default, Serialize, Deserialize)]
+struct BotState {
+	#[serde(default)]
+	rooms: HashMap<String, HashMap<String, CrateState>>,
+	tranquility: Option<f64>
+}
+
 lazy_static! {
-	static ref VERSION_MAP: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+	static ref VERSION_MAP: Mutex<HashMap<String, HashMap<String, CrateState>>> = Mutex::new(HashMap::new());
 	static ref CRATES_IO_CLIENT: crates_io_api::SyncClient = crates_io_api::SyncClient::new();
 	static ref OPTS: Options = Options::from_args();
+	static ref WORKER_COMMANDS: Mutex<Option<Sender<WorkerCommand>>> = Mutex::new(None);
+	static ref WORKER_STATUS: Mutex<WorkerStatus> = Mutex::new(WorkerStatus {
+		activity: WorkerActivity::Idle,
+		frequency: OPTS.update_frequency,
+		next_check: Instant::now(),
+		tranquility: OPTS.tranquility
+	});
+	static ref WORKER_HANDLE: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+// A worker panic shouldn't poison these locks for every command handler
+// too, so recover from poisoning instead of propagating it via `.unwrap()`.
+fn version_map() -> MutexGuard<'static, HashMap<String, HashMap<String, CrateState>>> {
+	VERSION_MAP.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn worker_status() -> MutexGuard<'static, WorkerStatus> {
+	WORKER_STATUS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// True once the update worker thread has exited, whether cleanly or via panic.
+fn worker_is_dead() -> bool {
+	WORKER_HANDLE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+		.as_ref()
+		.map(|handle| handle.is_finished())
+		.unwrap_or(false)
 }
 
 const HELP: &str = "
 !add <crate>... - Add crates to be watched;
 !list - List watched crates.
 !remove <crate>... - Remove crates from watch list.
+!pause - Pause the background update checks.
+!resume - Resume the background update checks.
+!status - Show whether the worker is active, idle or dead.
+!frequency <secs> - Change how often crates.io is checked.
+!tranquility [ratio] - View or change how gently crates.io is polled.
 !help - Show this dialog.
 ";
 
+// Commands sent to the update worker over its control channel.
+enum WorkerCommand {
+	Pause,
+	Resume,
+	SetFrequency(u64)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WorkerActivity {
+	Idle,
+	Active,
+	Paused,
+	Dead
+}
+
+struct WorkerStatus {
+	activity: WorkerActivity,
+	frequency: u64,
+	next_check: Instant,
+	tranquility: f64
+}
+
+fn send_worker_command(command: WorkerCommand) {
+	if let Some(sender) = WORKER_COMMANDS.lock().unwrap().as_ref() {
+		let _ = sender.send(command);
+	}
+}
+
 fn latest_version(crate_name: &str) -> Result<String, crates_io_api::Error> {
 	CRATES_IO_CLIENT.get_crate(crate_name)
-		.map(|info| info.versions[0].num.clone())
+		.and_then(|info| info.versions.get(0)
+			.map(|version| version.num.clone())
+			.ok_or(crates_io_api::Error::NotFound))
+}
+
+// Loads the state left behind by a previous run, if any. A missing or
+// unreadable file just means we start with no rooms watching anything and
+// the tranquility ratio from `--tranquility`.
+fn load_state(path: &Path) -> BotState {
+	fs::read_to_string(path)
+		.ok()
+		.and_then(|contents| serde_json::from_str(&contents).ok())
+		.unwrap_or_default()
+}
+
+// Writes the state via a temp-file-plus-rename so a crash mid-save can't
+// leave a corrupt or truncated store behind.
+fn save_state(path: &Path, rooms: &HashMap<String, HashMap<String, CrateState>>) {
+	let tmp_path = path.with_extension("tmp");
+
+	let state = BotState {
+		rooms: rooms.clone(),
+		tranquility: Some(worker_status().tranquility)
+	};
+
+	let serialized = match serde_json::to_string_pretty(&state) {
+		Ok(serialized) => serialized,
+		Err(_) => return
+	};
+
+	if fs::write(&tmp_path, serialized).is_ok() {
+		let _ = fs::rename(&tmp_path, path);
+	}
+}
+
+// With no `--rooms` given the bot is open-join and will watch crates for
+// whichever room asks it to; otherwise only the listed rooms are served.
+fn room_allowed(room: &str) -> bool {
+	OPTS.rooms.is_empty() || OPTS.rooms.iter().any(|allowed| allowed == room)
 }
 
 #[derive(StructOpt)]
@@ -33,42 +154,45 @@ struct Options {
 	username: String,
 	#[structopt(short, long)]
 	password: String,
-	#[structopt(short, long)]
-	room: String,
+	#[structopt(short, long, help = "Room IDs to serve; if omitted, the bot serves every room it is in")]
+	rooms: Vec<String>,
 	#[structopt(short, long, default_value = "https://matrix-client.matrix.org")]
 	homeserver_url: url::Url,
 	#[structopt(short, long, help = "Don't print verbosely")]
 	quiet: bool,
 	#[structopt(short = "f", long, default_value = "600", help = "How often to check crates.io")]
-	update_frequency: u64
+	update_frequency: u64,
+	#[structopt(short, long, default_value = "crates.json", help = "File to persist the watch list to")]
+	data_file: PathBuf,
+	#[structopt(short, long, default_value = "0", help = "How gently to poll crates.io: sleep ratio*request_time between checks")]
+	tranquility: f64
 }
 
 fn register_handles() -> StatelessHandler {
 	let mut handler = StatelessHandler::new();
 
 	handler.register_handle("list", |bot, msg, _| {
-		if msg.room != OPTS.room {
+		if !room_allowed(&msg.room) {
 			return HandleResult::StopHandling;
 		}
 
 		let output = {
-			let map = VERSION_MAP.lock().unwrap();
+			let rooms = version_map();
 
-			if map.is_empty() {
-				"No crates being watched".to_string()
-			} else {
-				map.iter()
-					.map(|(crate_name, version)| format!("`{}`:\t`{}`\n", crate_name, version))
-					.collect()
+			match rooms.get(&msg.room) {
+				Some(map) if !map.is_empty() => map.iter()
+					.map(|(crate_name, state)| format!("`{}`:\t`{}`\n", crate_name, state.version))
+					.collect(),
+				_ => "No crates being watched".to_string()
 			}
 		};
 
-		bot.send_message(&output, &OPTS.room, MessageType::TextMessage);
+		bot.send_message(&output, &msg.room, MessageType::TextMessage);
 		HandleResult::StopHandling
 	});
 
 	handler.register_handle("add", |bot, msg, tail| {
-		if msg.room != OPTS.room {
+		if !room_allowed(&msg.room) {
 			return HandleResult::StopHandling;
 		}
 
@@ -77,7 +201,10 @@ fn register_handles() -> StatelessHandler {
 			.map(|crate_name| {
 				match latest_version(crate_name) {
 					Ok(latest) => {
-						VERSION_MAP.lock().unwrap().insert(crate_name.to_string(), latest.clone());
+						let mut rooms = version_map();
+						rooms.entry(msg.room.clone()).or_insert_with(HashMap::new)
+							.insert(crate_name.to_string(), CrateState { version: latest.clone(), consecutive_failures: 0 });
+						save_state(&OPTS.data_file, &rooms);
 						format!("Added `{}` version `{}`\n", crate_name, latest)
 					},
 					Err(error) => match error {
@@ -92,66 +219,275 @@ fn register_handles() -> StatelessHandler {
 			output += "No crates being watched";
 		}
 
-		bot.send_message(&output, &OPTS.room, MessageType::TextMessage);
+		bot.send_message(&output, &msg.room, MessageType::TextMessage);
 		HandleResult::StopHandling
 	});
 
 	handler.register_handle("remove", |bot, msg, tail| {
-		if msg.room != OPTS.room {
+		if !room_allowed(&msg.room) {
 			return HandleResult::StopHandling;
 		}
 
 		let output: String = tail.split(' ')
 			.filter(|crate_name| !crate_name.is_empty())
 			.map(|crate_name| {
-				match VERSION_MAP.lock().unwrap().remove(crate_name) {
-					Some(version) => format!("Removed `{}` (version `{}`)\n", crate_name, version),
+				let mut rooms = version_map();
+				let removed = rooms.get_mut(&msg.room).and_then(|map| map.remove(crate_name));
+
+				match removed {
+					Some(state) => {
+						save_state(&OPTS.data_file, &rooms);
+						format!("Removed `{}` (version `{}`)\n", crate_name, state.version)
+					},
 					None => format!("Error: `{}` being watched\n", crate_name)
 				}
 			})
 			.collect();
 
-		bot.send_message(&output, &OPTS.room, MessageType::TextMessage);
+		bot.send_message(&output, &msg.room, MessageType::TextMessage);
+		HandleResult::StopHandling
+	});
+
+	handler.register_handle("pause", |bot, msg, _| {
+		if !room_allowed(&msg.room) {
+			return HandleResult::StopHandling;
+		}
+
+		send_worker_command(WorkerCommand::Pause);
+		bot.send_message("Paused update checks", &msg.room, MessageType::TextMessage);
+		HandleResult::StopHandling
+	});
+
+	handler.register_handle("resume", |bot, msg, _| {
+		if !room_allowed(&msg.room) {
+			return HandleResult::StopHandling;
+		}
+
+		send_worker_command(WorkerCommand::Resume);
+		bot.send_message("Resumed update checks", &msg.room, MessageType::TextMessage);
+		HandleResult::StopHandling
+	});
+
+	handler.register_handle("status", |bot, msg, _| {
+		if !room_allowed(&msg.room) {
+			return HandleResult::StopHandling;
+		}
+
+		let mut output = {
+			let status = worker_status();
+
+			// The loop only sets `Dead` when it exits cleanly after a
+			// channel disconnect; a panic kills the thread without getting
+			// to run that line, so a finished `JoinHandle` is the only
+			// reliable signal that the worker is actually gone.
+			if worker_is_dead() {
+				format!("Worker is dead. Tranquility: {}.", status.tranquility)
+			} else {
+				match status.activity {
+					WorkerActivity::Paused => format!(
+						"Worker is paused (will check every {}s once resumed). Tranquility: {}.",
+						status.frequency, status.tranquility
+					),
+					WorkerActivity::Dead => format!("Worker is dead. Tranquility: {}.", status.tranquility),
+					WorkerActivity::Idle | WorkerActivity::Active => {
+						let activity = if status.activity == WorkerActivity::Active { "checking crates.io" } else { "idle" };
+						let next_check = status.next_check.saturating_duration_since(Instant::now()).as_secs();
+
+						format!(
+							"Worker is {}. Next check in {}s (every {}s). Tranquility: {}.",
+							activity, next_check, status.frequency, status.tranquility
+						)
+					}
+				}
+			}
+		};
+
+		let erroring: String = version_map().get(&msg.room)
+			.map(|map| map.iter()
+				.filter(|(_, state)| state.consecutive_failures > 0)
+				.map(|(crate_name, state)| format!("\n`{}` is erroring ({} consecutive failures)", crate_name, state.consecutive_failures))
+				.collect())
+			.unwrap_or_default();
+
+		output += &erroring;
+
+		bot.send_message(&output, &msg.room, MessageType::TextMessage);
+		HandleResult::StopHandling
+	});
+
+	handler.register_handle("frequency", |bot, msg, tail| {
+		if !room_allowed(&msg.room) {
+			return HandleResult::StopHandling;
+		}
+
+		let output = match tail.trim().parse::<u64>() {
+			Ok(secs) if secs > 0 => {
+				send_worker_command(WorkerCommand::SetFrequency(secs));
+				format!("Now checking crates.io every {}s", secs)
+			},
+			_ => "Error: expected a positive number of seconds".to_string()
+		};
+
+		bot.send_message(&output, &msg.room, MessageType::TextMessage);
+		HandleResult::StopHandling
+	});
+
+	handler.register_handle("tranquility", |bot, msg, tail| {
+		if !room_allowed(&msg.room) {
+			return HandleResult::StopHandling;
+		}
+
+		let output = if tail.trim().is_empty() {
+			format!("Tranquility is currently {}", worker_status().tranquility)
+		} else {
+			match tail.trim().parse::<f64>() {
+				Ok(ratio) if ratio >= 0.0 => {
+					worker_status().tranquility = ratio;
+					save_state(&OPTS.data_file, &version_map());
+					format!("Tranquility set to {}", ratio)
+				},
+				_ => "Error: expected a non-negative number".to_string()
+			}
+		};
+
+		bot.send_message(&output, &msg.room, MessageType::TextMessage);
 		HandleResult::StopHandling
 	});
 
 	handler.register_handle("help", |bot, msg, _| {
-		if msg.room != OPTS.room {
+		if !room_allowed(&msg.room) {
 			return HandleResult::StopHandling;
 		}
 
-		bot.send_message(HELP, &OPTS.room, MessageType::TextMessage);
+		bot.send_message(HELP, &msg.room, MessageType::TextMessage);
 		HandleResult::StopHandling
 	});
 
 	handler
 }
 
-fn update_check_loop(update_bot: ActiveBot) {
+fn update_check_loop(update_bot: ActiveBot, commands: Receiver<WorkerCommand>) {
+	let mut paused = false;
+
 	loop {
-		sleep(Duration::from_secs(OPTS.update_frequency));
-
-		let output: String = VERSION_MAP.lock().unwrap().iter_mut()
-			.map(|(crate_name, version)| {
-				let latest = latest_version(&crate_name).unwrap();
-
-				if *version != latest {
-					let output = format!("`{}` updated from version `{}` to `{}`!", crate_name, version, latest);
-					*version = latest;
-					output
-				} else {
-					String::new()
-				}
-			})
+		let frequency = worker_status().frequency;
+
+		if paused {
+			worker_status().activity = WorkerActivity::Paused;
+
+			match commands.recv() {
+				Ok(WorkerCommand::Pause) => continue,
+				Ok(WorkerCommand::Resume) => { paused = false; continue; },
+				Ok(WorkerCommand::SetFrequency(secs)) => {
+					worker_status().frequency = secs;
+					continue;
+				},
+				Err(_) => break
+			}
+		}
+
+		{
+			let mut status = worker_status();
+			status.activity = WorkerActivity::Idle;
+			status.next_check = Instant::now() + Duration::from_secs(frequency);
+		}
+
+		match commands.recv_timeout(Duration::from_secs(frequency)) {
+			Ok(WorkerCommand::Pause) => { paused = true; continue; },
+			Ok(WorkerCommand::Resume) => continue,
+			Ok(WorkerCommand::SetFrequency(secs)) => {
+				worker_status().frequency = secs;
+				continue;
+			},
+			Err(RecvTimeoutError::Timeout) => {},
+			Err(RecvTimeoutError::Disconnected) => break
+		}
+
+		worker_status().activity = WorkerActivity::Active;
+
+		// Snapshot which crates to check and release the lock before the
+		// network calls (and the tranquility sleep between them) so command
+		// handlers don't block for the whole poll.
+		let snapshot: Vec<(String, String, CrateState)> = version_map().iter()
+			.flat_map(|(room, map)| map.iter()
+				.map(move |(crate_name, state)| (room.clone(), crate_name.clone(), state.clone())))
 			.collect();
 
-		if !output.is_empty() {
-			update_bot.send_message(&output, &OPTS.room, MessageType::TextMessage);
+		let mut changed = false;
+		let mut outputs: HashMap<String, String> = HashMap::new();
+
+		for (room, crate_name, mut state) in snapshot {
+			let started = Instant::now();
+			let result = latest_version(&crate_name);
+
+			let tranquility = worker_status().tranquility;
+			if tranquility > 0.0 {
+				sleep(started.elapsed().mul_f64(tranquility));
+			}
+
+			let message = match result {
+				Ok(latest) => {
+					if state.consecutive_failures > 0 {
+						state.consecutive_failures = 0;
+						changed = true;
+					}
+
+					if state.version != latest {
+						let message = format!("`{}` updated from version `{}` to `{}`!", crate_name, state.version, latest);
+						state.version = latest;
+						changed = true;
+						Some(message)
+					} else {
+						None
+					}
+				},
+				Err(error) => {
+					state.consecutive_failures += 1;
+					changed = true;
+
+					if state.consecutive_failures == FAILURE_REPORT_THRESHOLD {
+						Some(format!("Error checking `{}`: {} ({} consecutive failures, will keep retrying)", crate_name, error, state.consecutive_failures))
+					} else {
+						None
+					}
+				}
+			};
+
+			// A crate can be removed (or its room dropped) by a command
+			// handler while we're out here waiting on the network; only
+			// write the state back if it's still there to receive it.
+			if let Some(current) = version_map().get_mut(&room).and_then(|map| map.get_mut(&crate_name)) {
+				*current = state;
+			}
+
+			if let Some(message) = message {
+				let room_output = outputs.entry(room).or_insert_with(String::new);
+				room_output.push_str(&message);
+				room_output.push('\n');
+			}
+		}
+
+		if changed {
+			save_state(&OPTS.data_file, &version_map());
+		}
+
+		for (room, output) in outputs {
+			update_bot.send_message(&output, &room, MessageType::TextMessage);
 		}
 	}
+
+	worker_status().activity = WorkerActivity::Dead;
 }
 
 fn main() {
+	let state = load_state(&OPTS.data_file);
+
+	*version_map() = state.rooms;
+	worker_status().tranquility = state.tranquility.unwrap_or(OPTS.tranquility);
+
+	let (commands_tx, commands_rx) = mpsc::channel();
+	*WORKER_COMMANDS.lock().unwrap() = Some(commands_tx);
+
 	let mut bot = MatrixBot::new(register_handles());
 
 	bot.set_verbose(!OPTS.quiet);
@@ -164,7 +500,8 @@ fn main() {
 
 	let update_bot = active_bot.clone();
 
-	let handle = spawn(move || update_check_loop(update_bot));
+	let handle = spawn(move || update_check_loop(update_bot, commands_rx));
+	*WORKER_HANDLE.lock().unwrap() = Some(handle);
 
 	loop {
 		if !bot.recv_and_handle(&mut active_bot) {
@@ -172,5 +509,7 @@ fn main() {
 		}
 	}
 
-	handle.join().unwrap();
+	if let Some(handle) = WORKER_HANDLE.lock().unwrap().take() {
+		let _ = handle.join();
+	}
 }